@@ -0,0 +1,206 @@
+//! Decompression-bomb guards: caps on total decompressed output size and on
+//! the output/input expansion ratio, so that feeding `zflate -d` untrusted
+//! data can't exhaust memory or disk.
+
+use std::cell::Cell;
+use std::io::{self, BufRead, Read, Write};
+use std::rc::Rc;
+
+/// Minimum output, in bytes, before the ratio guard starts checking, so small
+/// inputs whose header overhead dominates the ratio don't trip it.
+const RATIO_WARMUP_BYTES: u64 = 1024 * 1024;
+
+/// Running output/input byte counts shared by a [`CountingReader`]/
+/// [`LimitedWriter`] pair, kept alive across multiple inputs that decompress
+/// to the same logical output (e.g. several files feeding one `--output
+/// FILE`), so `--max-output`/`--max-ratio` are enforced against the
+/// cumulative total rather than being reset per input.
+#[derive(Clone)]
+pub struct SharedCounters {
+    pub written: Rc<Cell<u64>>,
+    pub consumed: Rc<Cell<u64>>,
+}
+
+impl SharedCounters {
+    pub fn new() -> Self {
+        Self { written: Rc::new(Cell::new(0)), consumed: Rc::new(Cell::new(0)) }
+    }
+}
+
+impl Default for SharedCounters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Wraps a reader, counting bytes pulled through it into a shared counter so a
+/// paired [`LimitedWriter`] can compute an output/input ratio.
+pub struct CountingReader<R> {
+    inner: R,
+    consumed: Rc<Cell<u64>>,
+}
+
+impl<R> CountingReader<R> {
+    /// Wraps `inner`, returning the reader and a handle to its shared byte count.
+    pub fn new(inner: R) -> (Self, Rc<Cell<u64>>) {
+        Self::with_counter(inner, Rc::new(Cell::new(0)))
+    }
+
+    /// Wraps `inner`, counting into the given `consumed` counter instead of a
+    /// fresh one, so several readers feeding the same logical output (e.g.
+    /// multiple inputs writing to one `--output FILE`) can share a running total.
+    pub fn with_counter(inner: R, consumed: Rc<Cell<u64>>) -> (Self, Rc<Cell<u64>>) {
+        (Self { inner, consumed: Rc::clone(&consumed) }, consumed)
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.consumed.set(self.consumed.get() + n as u64);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for CountingReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.consumed.set(self.consumed.get() + amt as u64);
+        self.inner.consume(amt);
+    }
+}
+
+/// Wraps a writer, aborting with an error if the total bytes written exceed
+/// `max_output`, or if `written / consumed` exceeds `max_ratio` once past the
+/// warm-up threshold.
+pub struct LimitedWriter<W> {
+    inner: W,
+    written: Rc<Cell<u64>>,
+    consumed: Rc<Cell<u64>>,
+    max_output: Option<u64>,
+    max_ratio: Option<f64>,
+}
+
+impl<W: Write> LimitedWriter<W> {
+    pub fn new(inner: W, consumed: Rc<Cell<u64>>, max_output: Option<u64>, max_ratio: Option<f64>) -> Self {
+        Self::with_written(inner, Rc::new(Cell::new(0)), consumed, max_output, max_ratio)
+    }
+
+    /// Wraps `inner`, accumulating into the given `written` counter instead of
+    /// starting fresh, so several writes feeding the same logical output (e.g.
+    /// multiple inputs decompressed to one shared `--output FILE`) enforce
+    /// `max_output`/`max_ratio` against the cumulative total rather than each
+    /// input's own count.
+    pub fn with_written(
+        inner: W,
+        written: Rc<Cell<u64>>,
+        consumed: Rc<Cell<u64>>,
+        max_output: Option<u64>,
+        max_ratio: Option<f64>,
+    ) -> Self {
+        Self { inner, written, consumed, max_output, max_ratio }
+    }
+}
+
+impl<W: Write> Write for LimitedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        let written = self.written.get() + n as u64;
+        self.written.set(written);
+
+        if let Some(max_output) = self.max_output {
+            if written > max_output {
+                return Err(io::Error::other(format!(
+                    "decompression exceeded --max-output limit of {max_output} bytes \
+                     (possible decompression bomb)"
+                )));
+            }
+        }
+
+        if let Some(max_ratio) = self.max_ratio {
+            if written > RATIO_WARMUP_BYTES {
+                let ratio = written as f64 / self.consumed.get().max(1) as f64;
+                if ratio > max_ratio {
+                    return Err(io::Error::other(format!(
+                        "decompression ratio {ratio:.1}x exceeded --max-ratio limit of \
+                         {max_ratio}x (possible decompression bomb)"
+                    )));
+                }
+            }
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn writer(consumed: u64, max_output: Option<u64>, max_ratio: Option<f64>) -> LimitedWriter<Vec<u8>> {
+        LimitedWriter::new(Vec::new(), Rc::new(Cell::new(consumed)), max_output, max_ratio)
+    }
+
+    #[test]
+    fn max_output_cases() {
+        // (name, max_output, writes, expect_ok)
+        let cases: &[(&str, Option<u64>, &[usize], bool)] = &[
+            ("no limit allows any size", None, &[1024], true),
+            ("exactly at the limit is allowed", Some(10), &[10], true),
+            ("one byte over the limit errors", Some(10), &[11], false),
+            ("limit tripped by the sum of several writes", Some(10), &[5, 5, 1], false),
+        ];
+
+        for (name, max_output, writes, expect_ok) in cases {
+            let mut w = writer(0, *max_output, None);
+            let mut result = Ok(0);
+            for &len in *writes {
+                result = w.write(&vec![0u8; len]);
+                if result.is_err() {
+                    break;
+                }
+            }
+            assert_eq!(result.is_ok(), *expect_ok, "case: {name}");
+        }
+    }
+
+    #[test]
+    fn max_ratio_is_not_checked_before_the_warmup_threshold() {
+        // 1000:1 ratio, but total output is far below RATIO_WARMUP_BYTES.
+        let mut w = writer(1, Some(u64::MAX), Some(2.0));
+        assert!(w.write(&[0u8; 1000]).is_ok());
+    }
+
+    #[test]
+    fn max_ratio_trips_once_past_the_warmup_threshold() {
+        let consumed = 1;
+        let mut w = writer(consumed, None, Some(2.0));
+        // Past the warm-up threshold, output/input of far more than 2x should error.
+        let big = vec![0u8; (RATIO_WARMUP_BYTES + 1) as usize];
+        assert!(w.write(&big).is_err());
+    }
+
+    #[test]
+    fn max_output_is_enforced_cumulatively_across_shared_writers() {
+        // Simulates several inputs decompressed to one shared --output FILE: each
+        // gets its own LimitedWriter, but they share the same `written` counter.
+        let written = Rc::new(Cell::new(0));
+        let consumed = Rc::new(Cell::new(0));
+
+        let mut first =
+            LimitedWriter::with_written(Vec::new(), Rc::clone(&written), Rc::clone(&consumed), Some(15), None);
+        assert!(first.write(&[0u8; 10]).is_ok());
+
+        // A fresh writer over the same counters should see the prior writer's
+        // bytes already counted, not start back at zero.
+        let mut second = LimitedWriter::with_written(Vec::new(), Rc::clone(&written), consumed, Some(15), None);
+        assert!(second.write(&[0u8; 10]).is_err());
+    }
+}
@@ -1,15 +1,25 @@
+use std::cell::Cell;
 use std::fs::File;
 use std::io::{self, BufRead, BufReader, BufWriter, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
 use anyhow::Context;
 use clap::{Parser, ValueEnum};
 use flate2::{
-    bufread::{DeflateDecoder, DeflateEncoder, GzDecoder, GzEncoder, ZlibDecoder, ZlibEncoder},
-    Compression,
+    bufread::{DeflateDecoder, DeflateEncoder, MultiGzDecoder, ZlibDecoder, ZlibEncoder},
+    Compression, GzBuilder,
 };
+#[cfg(feature = "xz")]
+use xz2::bufread::{XzDecoder, XzEncoder};
+#[cfg(feature = "zstd")]
+use zstd::stream::read::{Decoder as ZstdDecoder, Encoder as ZstdEncoder};
 
-/// Compress or decompress zlib, gzip, or raw DEFLATE data streams
+mod bgzf;
+mod limits;
+
+/// Compress or decompress zlib/gzip/deflate streams, plus any optional
+/// formats this build was compiled with (see `--mode`)
 #[derive(Debug, Parser)]
 #[command(version)]
 struct Args {
@@ -17,31 +27,82 @@ struct Args {
     #[arg(short, long)]
     decompress: bool,
 
-    /// Header format: zlib, deflate, or gzip
+    /// Header format. Always includes zlib, deflate, and gzip; xz and/or
+    /// zstd are available if this build was compiled with their cargo
+    /// feature
     ///
-    /// Valid aliases include z, d, g, and gz
-    #[arg(short, long, value_enum, default_value_t, hide_possible_values = true)]
-    mode: Mode,
+    /// If omitted, the format and direction are auto-detected per input from
+    /// its extension or leading magic bytes, falling back to gzip
+    /// compression when nothing matches. With --decompress and no --mode,
+    /// only the leading magic bytes are checked, and an input they don't
+    /// identify is an error.
+    #[arg(short, long, value_enum, hide_possible_values = true)]
+    mode: Option<Mode>,
 
-    /// Compression level: from 1 (fastest) to 9 (best)
+    /// Compression level; valid ranges depend on the mode
+    ///
+    /// 1-9 for zlib/deflate/gzip; xz and zstd (if compiled in) accept their
+    /// own ranges reported in their respective --mode errors
     #[arg(
         short, long, value_name = "LEVEL",
-        default_value_t = Compression::default().level(),
-        value_parser = clap::value_parser!(u32).range(1..=9),
+        default_value_t = Compression::default().level() as i32,
+        value_parser = clap::value_parser!(i32),
         conflicts_with = "decompress",
+        allow_hyphen_values = true,
     )]
-    compression_level: u32,
+    compression_level: i32,
 
     /// Output filename. When no FILE, write to standard output
     #[arg(short, long, value_name = "FILE")]
     output: Option<PathBuf>,
 
+    /// Overwrite an existing output file instead of refusing to run
+    #[arg(short, long)]
+    force: bool,
+
+    /// Number of worker threads for parallel block-gzip (BGZF/Mgzip)
+    ///
+    /// Only supported in gzip mode; 1 uses the plain streaming codec. BGZF
+    /// members carry no room for header metadata, so compressing with values
+    /// above 1 requires --no-name and no --comment.
+    #[arg(
+        short = 'j', long, value_name = "N",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u64).range(1..),
+    )]
+    threads: u64,
+
+    /// Gzip comment field to store when compressing
+    #[arg(long, value_name = "TEXT")]
+    comment: Option<String>,
+
+    /// Save/restore the original file name and timestamp in gzip headers (default)
+    #[arg(short = 'N', long, overrides_with = "no_name")]
+    name: bool,
+
+    /// Don't save/restore the original file name and timestamp in gzip headers
+    #[arg(short = 'n', long = "no-name", overrides_with = "name")]
+    no_name: bool,
+
+    /// Abort decompression once more than SIZE bytes of output have been written
+    ///
+    /// Guards against decompression bombs when reading untrusted input.
+    #[arg(long, value_name = "SIZE")]
+    max_output: Option<u64>,
+
+    /// Abort decompression once the output/input size ratio exceeds RATIO
+    ///
+    /// Only checked after at least 1 MiB of output, to avoid false positives
+    /// on small files.
+    #[arg(long, value_name = "RATIO")]
+    max_ratio: Option<f64>,
+
     /// Input file(s). When no FILE, read standard input
     #[arg(value_name = "FILE")]
     files: Option<Vec<PathBuf>>,
 }
 
-#[derive(Debug, Clone, Copy, ValueEnum)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
 enum Mode {
     #[value(alias = "z")]
     Zlib,
@@ -49,27 +110,38 @@ enum Mode {
     Deflate,
     #[value(aliases = ["g", "gz"])]
     Gzip,
-}
-
-impl Default for Mode {
-    fn default() -> Self {
-        Self::Zlib
-    }
+    /// Requires the `xz` cargo feature.
+    #[cfg(feature = "xz")]
+    Xz,
+    /// Requires the `zstd` cargo feature.
+    #[cfg(feature = "zstd")]
+    #[value(alias = "zst")]
+    Zstd,
 }
 
 impl Mode {
-    fn compress<R, W>(self, level: Compression, input: &mut R, output: &mut W) -> io::Result<u64>
+    /// Compresses via the generic streaming codecs. Gzip is never dispatched
+    /// here: `transcode` always routes it through `compress_gzip` instead, to
+    /// apply header metadata.
+    fn compress<R, W>(self, level: i32, input: &mut R, output: &mut W) -> io::Result<u64>
     where
         R: BufRead + ?Sized,
         W: Write + ?Sized,
     {
         match self {
-            Mode::Zlib => io::copy(&mut ZlibEncoder::new(input, level), output),
-            Mode::Deflate => io::copy(&mut DeflateEncoder::new(input, level), output),
-            Mode::Gzip => io::copy(&mut GzEncoder::new(input, level), output),
+            Mode::Zlib => io::copy(&mut ZlibEncoder::new(input, Self::flate_level(level)?), output),
+            Mode::Deflate => io::copy(&mut DeflateEncoder::new(input, Self::flate_level(level)?), output),
+            Mode::Gzip => unreachable!("gzip compression always goes through compress_gzip"),
+            #[cfg(feature = "xz")]
+            Mode::Xz => io::copy(&mut XzEncoder::new(input, Self::xz_level(level)?), output),
+            #[cfg(feature = "zstd")]
+            Mode::Zstd => io::copy(&mut ZstdEncoder::new(input, Self::zstd_level(level)?)?, output),
         }
     }
 
+    /// Decompresses via the generic streaming codecs. Gzip is never
+    /// dispatched here: `transcode` always routes it through
+    /// `decompress_gzip` instead, to recover header metadata.
     fn decompress<R, W>(self, input: &mut R, output: &mut W) -> io::Result<u64>
     where
         R: BufRead + ?Sized,
@@ -78,40 +150,447 @@ impl Mode {
         match self {
             Mode::Zlib => io::copy(&mut ZlibDecoder::new(input), output),
             Mode::Deflate => io::copy(&mut DeflateDecoder::new(input), output),
-            Mode::Gzip => io::copy(&mut GzDecoder::new(input), output),
+            Mode::Gzip => unreachable!("gzip decompression always goes through decompress_gzip"),
+            #[cfg(feature = "xz")]
+            Mode::Xz => io::copy(&mut XzDecoder::new(input), output),
+            #[cfg(feature = "zstd")]
+            Mode::Zstd => io::copy(&mut ZstdDecoder::new(input)?, output),
         }
     }
-}
 
-fn run() -> anyhow::Result<()> {
-    let args = Args::parse();
+    /// Validates and converts a raw `--compression-level` value for the zlib
+    /// family (zlib, deflate, gzip), which all accept 1 (fastest) to 9 (best).
+    fn flate_level(level: i32) -> io::Result<Compression> {
+        if (1..=9).contains(&level) {
+            Ok(Compression::new(level as u32))
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("compression level must be between 1 and 9 (got {level})"),
+            ))
+        }
+    }
+
+    /// Validates a raw `--compression-level` value for xz, which accepts
+    /// presets 0 (fastest) to 9 (best).
+    #[cfg(feature = "xz")]
+    fn xz_level(level: i32) -> io::Result<u32> {
+        if (0..=9).contains(&level) {
+            Ok(level as u32)
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("compression level for xz must be between 0 and 9 (got {level})"),
+            ))
+        }
+    }
 
-    let mut output: Box<dyn Write> = match &args.output {
-        Some(path) => Box::new(BufWriter::new(
-            File::create(path).context("failed to open output file")?,
-        )),
-        None => Box::new(io::stdout()),
-    };
-
-    let comp_level = Compression::new(args.compression_level);
-    let mut transcode = |input: &mut dyn BufRead| -> io::Result<u64> {
-        if args.decompress {
-            args.mode.decompress(input, &mut output)
+    /// Validates a raw `--compression-level` value for zstd, which accepts
+    /// -22 (fastest) to 22 (best).
+    #[cfg(feature = "zstd")]
+    fn zstd_level(level: i32) -> io::Result<i32> {
+        if (-22..=22).contains(&level) {
+            Ok(level)
         } else {
-            args.mode.compress(comp_level, input, &mut output)
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("compression level for zstd must be between -22 and 22 (got {level})"),
+            ))
+        }
+    }
+
+    /// The extension this mode's compressed output conventionally uses.
+    fn extension(self) -> &'static str {
+        match self {
+            Mode::Zlib => "zz",
+            Mode::Deflate => "deflate",
+            Mode::Gzip => "gz",
+            #[cfg(feature = "xz")]
+            Mode::Xz => "xz",
+            #[cfg(feature = "zstd")]
+            Mode::Zstd => "zst",
+        }
+    }
+
+    /// Maps a file extension (without the leading dot) to the mode it implies.
+    fn from_extension(ext: &str) -> Option<Self> {
+        match ext.to_ascii_lowercase().as_str() {
+            "gz" => Some(Mode::Gzip),
+            "zz" | "zlib" => Some(Mode::Zlib),
+            "deflate" => Some(Mode::Deflate),
+            #[cfg(feature = "xz")]
+            "xz" => Some(Mode::Xz),
+            #[cfg(feature = "zstd")]
+            "zst" | "zstd" => Some(Mode::Zstd),
+            _ => None,
         }
-    };
+    }
+
+    /// Maps a stream's leading bytes to the mode its magic number implies.
+    fn from_magic(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [0x1f, 0x8b, ..] => Some(Mode::Gzip),
+            [0x78, 0x01 | 0x9c | 0xda, ..] => Some(Mode::Zlib),
+            #[cfg(feature = "xz")]
+            [0xfd, b'7', b'z', b'X', b'Z', 0x00, ..] => Some(Mode::Xz),
+            #[cfg(feature = "zstd")]
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Some(Mode::Zstd),
+            _ => None,
+        }
+    }
+}
 
-    if let Some(files) = args.files {
-        for path in files {
-            let mut file = BufReader::new(
-                File::open(&path)
-                    .with_context(|| format!("failed to open input file '{}'", path.display()))?,
+/// The resolved format and direction for a single input, after applying any
+/// explicit `--mode`/`--decompress` flags or auto-detection.
+#[derive(Debug)]
+struct Action {
+    mode: Mode,
+    decompress: bool,
+}
+
+impl Action {
+    /// A short human-readable description for error messages, e.g. "decompress as gzip".
+    fn describe(&self) -> String {
+        let verb = if self.decompress { "decompress" } else { "compress" };
+        format!("{verb} as {:?}", self.mode).to_lowercase()
+    }
+}
+
+/// Resolves the [`Action`] to take for one input, given its path (if any, for
+/// extension sniffing) and its leading bytes (for magic-number sniffing).
+fn resolve_action(args: &Args, path: Option<&Path>, magic: &[u8]) -> anyhow::Result<Action> {
+    if let Some(mode) = args.mode {
+        return Ok(Action { mode, decompress: args.decompress });
+    }
+
+    if args.decompress {
+        let mode = Mode::from_magic(magic)
+            .context("could not detect compression format from input; pass --mode explicitly")?;
+        return Ok(Action { mode, decompress: true });
+    }
+
+    let ext_mode = path
+        .and_then(Path::extension)
+        .and_then(|ext| ext.to_str())
+        .and_then(Mode::from_extension);
+    match ext_mode.or_else(|| Mode::from_magic(magic)) {
+        Some(mode) => Ok(Action { mode, decompress: true }),
+        None => Ok(Action { mode: Mode::Gzip, decompress: false }),
+    }
+}
+
+/// Derives an output path for `input` when `--output` wasn't given, by
+/// appending the mode's extension when compressing or stripping a matching
+/// one when decompressing.
+fn derived_output_path(input: &Path, action: &Action) -> PathBuf {
+    if action.decompress {
+        let matches_mode = input
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(Mode::from_extension)
+            == Some(action.mode);
+        if matches_mode {
+            return input.with_extension("");
+        }
+    }
+
+    let mut name = input.as_os_str().to_owned();
+    if action.decompress {
+        name.push(".out");
+    } else {
+        name.push(".");
+        name.push(action.mode.extension());
+    }
+    PathBuf::from(name)
+}
+
+/// Gzip header metadata to embed when compressing, built from `--comment` and,
+/// when `--no-name` isn't set, the input's file name and mtime.
+struct GzipMeta {
+    filename: Option<Vec<u8>>,
+    mtime: u32,
+    comment: Option<Vec<u8>>,
+}
+
+impl GzipMeta {
+    fn for_input(args: &Args, path: Option<&Path>) -> Self {
+        let honor_name = !args.no_name;
+        let filename = honor_name
+            .then(|| path.and_then(Path::file_name))
+            .flatten()
+            .map(|name| name.to_string_lossy().into_owned().into_bytes());
+        let mtime = honor_name
+            .then(|| path.and_then(|p| std::fs::metadata(p).ok()?.modified().ok()))
+            .flatten()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs() as u32);
+        let comment = args.comment.clone().map(String::into_bytes);
+        GzipMeta { filename, mtime, comment }
+    }
+
+    fn apply(self, mut builder: GzBuilder) -> GzBuilder {
+        if let Some(filename) = self.filename {
+            builder = builder.filename(filename);
+        }
+        if let Some(comment) = self.comment {
+            builder = builder.comment(comment);
+        }
+        builder.mtime(self.mtime)
+    }
+}
+
+/// Compresses `input` to `output` as a gzip stream carrying `meta`'s header
+/// metadata (filename, mtime, comment).
+fn compress_gzip<R, W>(level: i32, meta: GzipMeta, input: &mut R, output: &mut W) -> io::Result<u64>
+where
+    R: BufRead + ?Sized,
+    W: Write + ?Sized,
+{
+    let builder = meta.apply(GzBuilder::new());
+    io::copy(&mut builder.read(input, Mode::flate_level(level)?), output)
+}
+
+/// Decompresses a gzip stream, also returning the stored file name from its
+/// header, if any.
+fn decompress_gzip<R, W>(input: &mut R, output: &mut W) -> io::Result<(u64, Option<Vec<u8>>)>
+where
+    R: BufRead + ?Sized,
+    W: Write + ?Sized,
+{
+    let mut decoder = MultiGzDecoder::new(input);
+    let filename = decoder.header().and_then(|h| h.filename()).map(|b| b.to_vec());
+    let written = io::copy(&mut decoder, output)?;
+    Ok((written, filename))
+}
+
+/// Resolves the file name stored in a gzip header into a sibling path next to
+/// `input`, stripping any directory components for safety.
+///
+/// The header name is untrusted input: if it has no usable file-name
+/// component at all (e.g. "..", ".", or empty), fall back to
+/// [`derived_output_path`]'s default rather than using the raw string, which
+/// could otherwise resolve outside of `input`'s directory.
+fn named_output_path(input: &Path, header_name: &[u8], action: &Action) -> PathBuf {
+    let name = String::from_utf8_lossy(header_name).into_owned();
+    match Path::new(&name).file_name() {
+        Some(name) => input.with_file_name(name),
+        None => derived_output_path(input, action),
+    }
+}
+
+/// Reports whether `a` and `b` resolve to the same file on disk. Returns
+/// `false` (rather than erroring) if either path can't be canonicalized,
+/// e.g. because it doesn't exist yet.
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    std::fs::canonicalize(a).ok().zip(std::fs::canonicalize(b).ok()).is_some_and(|(a, b)| a == b)
+}
+
+/// Opens `out_path` for writing, refusing to silently clobber another file.
+///
+/// Errors out if `out_path` is the same file as `input_path` (which would be
+/// truncated out from under the reader still open on it), and, more
+/// generally, if `out_path` already exists and `--force` wasn't passed,
+/// matching `gzip`'s refusal to overwrite without confirmation.
+fn create_output_file(out_path: &Path, input_path: Option<&Path>, force: bool) -> anyhow::Result<File> {
+    if let Some(input_path) = input_path {
+        if is_same_file(out_path, input_path) {
+            anyhow::bail!(
+                "refusing to write to '{}': it is the same file as input '{}'",
+                out_path.display(),
+                input_path.display()
             );
-            transcode(&mut file)?;
         }
+    }
+    if out_path.exists() && !force {
+        anyhow::bail!("output file '{}' already exists (use --force to overwrite)", out_path.display());
+    }
+    File::create(out_path).with_context(|| format!("failed to open output file '{}'", out_path.display()))
+}
+
+/// Stamps `file`'s modification time with `mtime` (gzip header seconds since
+/// the Unix epoch), matching `gzip -N`'s name/timestamp restore behavior.
+fn restore_mtime(file: &File, mtime: u32) -> io::Result<()> {
+    file.set_modified(std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(mtime as u64))
+}
+
+/// Validates that `action`'s resolved mode/direction is compatible with
+/// `--threads`, before any output file has been touched.
+///
+/// BGZF (threads > 1) only supports gzip, and its members carry no room for
+/// header metadata, so compressing with threads > 1 additionally requires
+/// --no-name and no --comment.
+fn validate_threads(args: &Args, threads: usize, action: &Action) -> anyhow::Result<()> {
+    if threads > 1 && action.mode != Mode::Gzip {
+        anyhow::bail!("--threads > 1 is only supported in gzip mode");
+    }
+
+    if threads > 1 && !action.decompress && (args.comment.is_some() || !args.no_name) {
+        anyhow::bail!(
+            "--comment and gzip header name/mtime storage are not supported with \
+             --threads > 1; pass --no-name and omit --comment to use parallel compression"
+        );
+    }
+
+    Ok(())
+}
+
+/// Compresses or decompresses one input per `action`, embedding/reading gzip
+/// header metadata when applicable.
+///
+/// `shared_counters`, when given, accumulates `--max-output`/`--max-ratio`
+/// totals across multiple calls writing to the same logical output, e.g.
+/// several inputs feeding one shared `--output FILE`. Without it, each call
+/// enforces the limits against its own input alone.
+///
+/// Callers must have already validated `action` against `--threads` via
+/// [`validate_threads`]; this only performs the transcoding itself, so that
+/// validation can run before any output file is opened.
+#[allow(clippy::too_many_arguments)]
+fn transcode(
+    args: &Args,
+    comp_level: i32,
+    threads: usize,
+    action: &Action,
+    path: Option<&Path>,
+    input: &mut dyn BufRead,
+    output: &mut dyn Write,
+    shared_counters: Option<&limits::SharedCounters>,
+) -> io::Result<u64> {
+    if action.decompress {
+        let (written, consumed) = match shared_counters {
+            Some(shared) => (Rc::clone(&shared.written), Rc::clone(&shared.consumed)),
+            None => (Rc::new(Cell::new(0)), Rc::new(Cell::new(0))),
+        };
+        let (mut input, consumed) = limits::CountingReader::with_counter(input, consumed);
+        let mut output =
+            limits::LimitedWriter::with_written(output, written, consumed, args.max_output, args.max_ratio);
+        if threads > 1 {
+            bgzf::decompress(threads, &mut input, &mut output)
+        } else if action.mode == Mode::Gzip {
+            decompress_gzip(&mut input, &mut output).map(|(written, _)| written)
+        } else {
+            action.mode.decompress(&mut input, &mut output)
+        }
+    } else if threads > 1 {
+        bgzf::compress(threads, Mode::flate_level(comp_level)?, input, output)
+    } else if action.mode == Mode::Gzip {
+        compress_gzip(comp_level, GzipMeta::for_input(args, path), input, output)
     } else {
-        transcode(&mut io::stdin().lock())?;
+        action.mode.compress(comp_level, input, output)
+    }
+}
+
+fn run() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    let comp_level = args.compression_level;
+    let threads = args.threads as usize;
+
+    match &args.files {
+        Some(files) if !files.is_empty() => {
+            // Open every input and resolve/validate its action before any output
+            // file is touched, so a validation failure on input N can't have
+            // already truncated an output file opened for inputs before it.
+            let mut inputs = Vec::with_capacity(files.len());
+            for path in files {
+                let mut file = BufReader::new(
+                    File::open(path)
+                        .with_context(|| format!("failed to open input file '{}'", path.display()))?,
+                );
+                let magic = file.fill_buf().context("failed to read input file")?.to_vec();
+                let action = resolve_action(&args, Some(path), &magic)?;
+                validate_threads(&args, threads, &action)?;
+                inputs.push((path, file, action));
+            }
+
+            // An explicit --output is a single shared sink for every input, matching
+            // the prior behavior; otherwise each input gets its own derived path.
+            let mut shared_output = args
+                .output
+                .as_ref()
+                .map(|path| -> anyhow::Result<Box<dyn Write>> {
+                    for (input, _, _) in &inputs {
+                        if is_same_file(path, input) {
+                            anyhow::bail!(
+                                "refusing to write to '{}': it is the same file as input '{}'",
+                                path.display(),
+                                input.display()
+                            );
+                        }
+                    }
+                    // Auto-detection resolves an independent action per input, but a
+                    // shared output is a single concatenated stream, so every input
+                    // feeding it must agree on format and direction.
+                    let (first_mode, first_decompress) = {
+                        let (_, _, first) = &inputs[0];
+                        (first.mode, first.decompress)
+                    };
+                    if let Some((mismatched, _, action)) =
+                        inputs.iter().find(|(_, _, a)| a.mode != first_mode || a.decompress != first_decompress)
+                    {
+                        anyhow::bail!(
+                            "inputs resolve to different formats/directions for shared --output '{}': \
+                             '{}' would {}, which doesn't match the rest; pass --mode/--decompress \
+                             explicitly so all inputs resolve the same way, or write to separate outputs",
+                            path.display(),
+                            mismatched.display(),
+                            action.describe(),
+                        );
+                    }
+                    Ok(Box::new(BufWriter::new(create_output_file(path, None, args.force)?)))
+                })
+                .transpose()?;
+            // Shared across every input writing to `shared_output`, so
+            // --max-output/--max-ratio see the cumulative total rather than
+            // resetting per file.
+            let shared_limits = shared_output.is_some().then(limits::SharedCounters::new);
+
+            for (path, mut file, action) in inputs {
+                if let Some(output) = shared_output.as_deref_mut() {
+                    transcode(&args, comp_level, threads, &action, Some(path), &mut file, output, shared_limits.as_ref())?;
+                    continue;
+                }
+
+                // When restoring stored names/timestamps, the header must be read
+                // before the output path can be chosen, so gzip decompression is
+                // special-cased.
+                if action.decompress && action.mode == Mode::Gzip && threads <= 1 && !args.no_name {
+                    let (mut counted, consumed) = limits::CountingReader::new(&mut file);
+                    let mut decoder = MultiGzDecoder::new(&mut counted);
+                    let header_name = decoder.header().and_then(|h| h.filename()).map(<[u8]>::to_vec);
+                    let header_mtime = decoder.header().map(|h| h.mtime()).filter(|&mtime| mtime != 0);
+                    let out_path = header_name
+                        .map(|name| named_output_path(path, &name, &action))
+                        .unwrap_or_else(|| derived_output_path(path, &action));
+                    let out_file = create_output_file(&out_path, Some(path), args.force)?;
+                    let mtime_handle = header_mtime.and_then(|_| out_file.try_clone().ok());
+                    let out = BufWriter::new(out_file);
+                    let mut out = limits::LimitedWriter::new(out, consumed, args.max_output, args.max_ratio);
+                    io::copy(&mut decoder, &mut out)?;
+                    out.flush()?;
+                    if let (Some(mtime), Some(handle)) = (header_mtime, mtime_handle) {
+                        restore_mtime(&handle, mtime)?;
+                    }
+                    continue;
+                }
+
+                let out_path = derived_output_path(path, &action);
+                let mut out = BufWriter::new(create_output_file(&out_path, Some(path), args.force)?);
+                transcode(&args, comp_level, threads, &action, Some(path), &mut file, &mut out, None)?;
+            }
+        }
+        _ => {
+            let mut input = io::stdin().lock();
+            let magic = input.fill_buf().context("failed to read standard input")?.to_vec();
+            let action = resolve_action(&args, None, &magic)?;
+            validate_threads(&args, threads, &action)?;
+
+            let mut output: Box<dyn Write> = match &args.output {
+                Some(path) => Box::new(BufWriter::new(create_output_file(path, None, args.force)?)),
+                None => Box::new(io::stdout()),
+            };
+            transcode(&args, comp_level, threads, &action, None, &mut input, &mut output, None)?;
+        }
     }
 
     Ok(())
@@ -123,3 +602,243 @@ fn main() {
         std::process::exit(1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flate_level_boundary_cases() {
+        // (level, expect_ok)
+        let cases: &[(i32, bool)] = &[(0, false), (1, true), (9, true), (10, false)];
+        for (level, expect_ok) in cases {
+            assert_eq!(Mode::flate_level(*level).is_ok(), *expect_ok, "level: {level}");
+        }
+    }
+
+    #[cfg(feature = "xz")]
+    #[test]
+    fn xz_level_boundary_cases() {
+        // (level, expect_ok)
+        let cases: &[(i32, bool)] = &[(-1, false), (0, true), (9, true), (10, false)];
+        for (level, expect_ok) in cases {
+            assert_eq!(Mode::xz_level(*level).is_ok(), *expect_ok, "level: {level}");
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_level_boundary_cases() {
+        // (level, expect_ok)
+        let cases: &[(i32, bool)] = &[(-23, false), (-22, true), (22, true), (23, false)];
+        for (level, expect_ok) in cases {
+            assert_eq!(Mode::zstd_level(*level).is_ok(), *expect_ok, "level: {level}");
+        }
+    }
+
+    fn args(mode: Option<Mode>, decompress: bool) -> Args {
+        Args {
+            decompress,
+            mode,
+            compression_level: Compression::default().level() as i32,
+            output: None,
+            force: false,
+            threads: 1,
+            comment: None,
+            name: false,
+            no_name: false,
+            max_output: None,
+            max_ratio: None,
+            files: None,
+        }
+    }
+
+    /// One [`resolve_action`] case: the inputs to resolve, and the expected
+    /// resulting mode and direction.
+    struct ResolveCase {
+        name: &'static str,
+        args: Args,
+        path: Option<&'static str>,
+        magic: &'static [u8],
+        expected_mode: Mode,
+        expected_decompress: bool,
+    }
+
+    #[test]
+    fn resolve_action_cases() {
+        const GZIP_MAGIC: [u8; 4] = [0x1f, 0x8b, 0x08, 0x00];
+        const ZLIB_MAGIC: [u8; 4] = [0x78, 0x9c, 0x00, 0x00];
+        const UNKNOWN_MAGIC: [u8; 4] = [0x00, 0x01, 0x02, 0x03];
+
+        let cases = [
+            ResolveCase {
+                name: "explicit --mode wins over auto-detection",
+                args: args(Some(Mode::Zlib), false),
+                path: Some("input.gz"),
+                magic: &GZIP_MAGIC,
+                expected_mode: Mode::Zlib,
+                expected_decompress: false,
+            },
+            ResolveCase {
+                name: "explicit --decompress with no --mode detects from magic",
+                args: args(None, true),
+                path: None,
+                magic: &GZIP_MAGIC,
+                expected_mode: Mode::Gzip,
+                expected_decompress: true,
+            },
+            ResolveCase {
+                name: "auto mode detects gzip from extension",
+                args: args(None, false),
+                path: Some("input.gz"),
+                magic: &UNKNOWN_MAGIC,
+                expected_mode: Mode::Gzip,
+                expected_decompress: true,
+            },
+            ResolveCase {
+                name: "auto mode detects zlib from magic when extension is unknown",
+                args: args(None, false),
+                path: Some("input.bin"),
+                magic: &ZLIB_MAGIC,
+                expected_mode: Mode::Zlib,
+                expected_decompress: true,
+            },
+            ResolveCase {
+                name: "auto mode falls back to gzip compression when nothing matches",
+                args: args(None, false),
+                path: Some("input.txt"),
+                magic: &UNKNOWN_MAGIC,
+                expected_mode: Mode::Gzip,
+                expected_decompress: false,
+            },
+        ];
+
+        for case in cases {
+            let path = case.path.map(Path::new);
+            let action = resolve_action(&case.args, path, case.magic)
+                .unwrap_or_else(|e| panic!("case {}: {e}", case.name));
+            assert_eq!(action.mode, case.expected_mode, "case: {}", case.name);
+            assert_eq!(action.decompress, case.expected_decompress, "case: {}", case.name);
+        }
+    }
+
+    #[test]
+    fn resolve_action_decompress_without_detectable_magic_errors() {
+        let result = resolve_action(&args(None, true), Some(Path::new("input")), &[0x00, 0x01]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn derived_output_path_cases() {
+        let cases: &[(&str, &str, Mode, bool, &str)] = &[
+            ("appends extension when compressing", "file.txt", Mode::Gzip, false, "file.txt.gz"),
+            ("strips matching extension when decompressing", "file.txt.gz", Mode::Gzip, true, "file.txt"),
+            (
+                "appends .out when decompressing and the extension doesn't match the mode",
+                "file.weird",
+                Mode::Gzip,
+                true,
+                "file.weird.out",
+            ),
+        ];
+
+        for (name, input, mode, decompress, expected) in cases {
+            let action = Action { mode: *mode, decompress: *decompress };
+            let out = derived_output_path(Path::new(input), &action);
+            assert_eq!(out, PathBuf::from(expected), "case: {name}");
+        }
+    }
+
+    /// Returns a fresh path under the system temp dir, unique per test run.
+    fn temp_path(label: &str) -> PathBuf {
+        use std::sync::atomic::{AtomicU32, Ordering};
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir().join(format!("zflate-test-{label}-{}-{n}", std::process::id()))
+    }
+
+    #[test]
+    fn gzip_meta_round_trip_preserves_name_and_mtime() {
+        let input_path = temp_path("gzip-meta-input.txt");
+        std::fs::write(&input_path, b"hello").unwrap();
+        let expected_mtime = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_577_836_800);
+        File::open(&input_path).unwrap().set_modified(expected_mtime).unwrap();
+
+        let meta = GzipMeta::for_input(&args(Some(Mode::Gzip), false), Some(&input_path));
+        assert_eq!(meta.filename.as_deref(), Some(input_path.file_name().unwrap().as_encoded_bytes()));
+        assert_eq!(meta.mtime, 1_577_836_800);
+
+        let mut compressed = Vec::new();
+        let written = compress_gzip(
+            Compression::default().level() as i32,
+            meta,
+            &mut BufReader::new(File::open(&input_path).unwrap()),
+            &mut compressed,
+        )
+        .unwrap();
+        assert!(written > 0);
+
+        let decoder = MultiGzDecoder::new(compressed.as_slice());
+        let header = decoder.header().unwrap();
+        assert_eq!(header.filename(), Some(input_path.file_name().unwrap().as_encoded_bytes()));
+        assert_eq!(header.mtime(), 1_577_836_800);
+
+        std::fs::remove_file(&input_path).unwrap();
+    }
+
+    fn bare_meta() -> GzipMeta {
+        GzipMeta { filename: None, mtime: 0, comment: None }
+    }
+
+    #[test]
+    fn decompress_gzip_reads_every_concatenated_member() {
+        let level = Compression::default().level() as i32;
+
+        let mut first = Vec::new();
+        compress_gzip(level, bare_meta(), &mut b"hello, ".as_slice(), &mut first).unwrap();
+        let mut second = Vec::new();
+        compress_gzip(level, bare_meta(), &mut b"world!".as_slice(), &mut second).unwrap();
+
+        let mut concatenated = first;
+        concatenated.extend_from_slice(&second);
+
+        let mut output = Vec::new();
+        let (written, _) = decompress_gzip(&mut concatenated.as_slice(), &mut output).unwrap();
+        assert_eq!(written, b"hello, world!".len() as u64);
+        assert_eq!(output, b"hello, world!");
+    }
+
+    #[test]
+    fn restore_mtime_stamps_the_output_file() {
+        let path = temp_path("restore-mtime.txt");
+        std::fs::write(&path, b"data").unwrap();
+
+        restore_mtime(&File::open(&path).unwrap(), 1_577_836_800).unwrap();
+
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        let secs = modified.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        assert_eq!(secs, 1_577_836_800);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn create_output_file_refuses_same_file_and_unforced_overwrite() {
+        let input_path = temp_path("create-output-input.txt");
+        std::fs::write(&input_path, b"input").unwrap();
+
+        // Refuses when output and input resolve to the same file.
+        assert!(create_output_file(&input_path, Some(&input_path), false).is_err());
+
+        // Refuses an existing, different output file without --force...
+        let output_path = temp_path("create-output-output.txt");
+        std::fs::write(&output_path, b"stale").unwrap();
+        assert!(create_output_file(&output_path, Some(&input_path), false).is_err());
+
+        // ...but proceeds with --force.
+        assert!(create_output_file(&output_path, Some(&input_path), true).is_ok());
+
+        std::fs::remove_file(&input_path).unwrap();
+        std::fs::remove_file(&output_path).unwrap();
+    }
+}